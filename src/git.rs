@@ -0,0 +1,395 @@
+//! In-process access to the repository, backed by `gix`.
+//!
+//! This replaces the previous approach of shelling out to `git` for every
+//! step (`git rev-parse`, `git diff-index`, and eventually `git hash-object`
+//! / `git update-index`). Opening the repository once and talking to its
+//! object database and index directly avoids spawning a process per staged
+//! file and removes the need to parse `git diff-index` output with a regex.
+//!
+//! The `subprocess` feature keeps the old behavior around as a fallback for
+//! environments where only a `git` binary is available (no local object
+//! database access, e.g. some sandboxes and thin checkouts).
+
+use std::path::PathBuf;
+
+#[cfg(not(feature = "subprocess"))]
+use log::warn;
+
+#[cfg(feature = "subprocess")]
+use std::process;
+
+/// A staged file selected by `git diff-index --cached --diff-filter=AM --no-renames HEAD`.
+pub struct StagedEntry {
+    pub dst_mode: String,
+    pub dst_hash: gix::ObjectId,
+    pub path: String,
+}
+
+/// A single index entry to repoint at a newly-written blob, as produced by
+/// formatting one staged file. Applied in a batch by `update_index_entries`
+/// so that parallel per-file formatting never touches `.git/index` directly.
+pub struct IndexUpdate {
+    pub path: String,
+    pub id: gix::ObjectId,
+    pub mode: String,
+}
+
+/// Handle to the repository the tool is running in.
+///
+/// Cheap to clone: `gix::Repository` shares its object database and caches
+/// across clones, which is the supported way to give each worker thread its
+/// own handle for concurrent reads/writes.
+#[derive(Clone)]
+pub struct Repo {
+    #[cfg(not(feature = "subprocess"))]
+    inner: gix::Repository,
+    root: PathBuf,
+}
+
+impl Repo {
+    /// Discover the repository starting from the current directory.
+    pub fn discover() -> Self {
+        #[cfg(not(feature = "subprocess"))]
+        {
+            let inner = gix::discover(".").expect("Failed to discover git repository");
+            let root = inner
+                .work_dir()
+                .expect("repository has no working tree")
+                .to_path_buf();
+            Self { inner, root }
+        }
+        #[cfg(feature = "subprocess")]
+        {
+            Self {
+                root: get_git_root_subprocess(),
+            }
+        }
+    }
+
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    /// Enumerate staged add/modify entries, equivalent to
+    /// `git diff-index --cached --diff-filter=AM --no-renames HEAD`.
+    pub fn staged_entries(&self) -> Vec<StagedEntry> {
+        #[cfg(not(feature = "subprocess"))]
+        {
+            self.staged_entries_gix()
+        }
+        #[cfg(feature = "subprocess")]
+        {
+            staged_entries_subprocess()
+        }
+    }
+
+    /// Load a fresh, independently-mutable view of the on-disk index.
+    ///
+    /// `gix::Repository::index_or_load_from_head()` hands back a read-only,
+    /// `Arc`-shared snapshot in the common case (only `InMemory` indices are
+    /// mutable), which isn't enough for `update_index_entries` below. Going
+    /// straight to `gix_index::File::at` sidesteps that and gives every
+    /// caller its own copy to read or mutate.
+    #[cfg(not(feature = "subprocess"))]
+    fn load_index(&self) -> gix::index::File {
+        gix::index::File::at(
+            self.inner.git_dir().join("index"),
+            self.inner.object_hash(),
+            false,
+            gix::index::decode::Options::default(),
+        )
+        .expect("Failed to load index")
+    }
+
+    #[cfg(not(feature = "subprocess"))]
+    fn staged_entries_gix(&self) -> Vec<StagedEntry> {
+        let index = self.load_index();
+        let head_tree = self.inner.head_commit().ok().and_then(|c| c.tree().ok());
+        let mut scratch = Vec::new();
+
+        let mut entries = Vec::new();
+        for entry in index.entries() {
+            let path = entry.path(&index).to_string();
+            let mode = format!("{:o}", entry.mode.bits());
+            let unchanged_from_head = head_tree
+                .as_ref()
+                .and_then(|tree| {
+                    tree.lookup_entry_by_path(&path, &mut scratch)
+                        .ok()
+                        .flatten()
+                })
+                .map(|head_entry| head_entry.oid() == entry.id)
+                .unwrap_or(false);
+            if unchanged_from_head {
+                // Neither added nor modified relative to HEAD.
+                continue;
+            }
+            entries.push(StagedEntry {
+                dst_mode: mode,
+                dst_hash: entry.id,
+                path,
+            });
+        }
+        entries
+    }
+
+    /// Enumerate paths that differ between `rev` and the working set (what's
+    /// committed on the current branch since `rev`, plus anything still
+    /// uncommitted), like `git diff --name-only --diff-filter=AM <rev>`: paths
+    /// deleted since `rev` are not listed.
+    pub fn changed_against(&self, rev: &str) -> Vec<String> {
+        #[cfg(not(feature = "subprocess"))]
+        {
+            self.changed_against_gix(rev)
+        }
+        #[cfg(feature = "subprocess")]
+        {
+            changed_against_subprocess(&self.root, rev)
+        }
+    }
+
+    #[cfg(not(feature = "subprocess"))]
+    fn changed_against_gix(&self, rev: &str) -> Vec<String> {
+        let base_tree = self
+            .inner
+            .rev_parse_single(rev)
+            .unwrap_or_else(|_| panic!("Failed to resolve revision {}", rev))
+            .object()
+            .expect("Failed to load revision object")
+            .peel_to_tree()
+            .expect("Revision does not resolve to a tree");
+        let head_tree = self
+            .inner
+            .head_commit()
+            .expect("Failed to resolve HEAD")
+            .tree()
+            .expect("HEAD has no tree");
+
+        let mut changed = std::collections::BTreeSet::new();
+        base_tree
+            .changes()
+            .expect("Failed to set up tree diff")
+            .track_path()
+            .for_each_to_obtain_tree(&head_tree, |change| {
+                use gix::object::tree::diff::change::Event;
+                if let Event::Addition { entry_mode, .. } | Event::Modification { entry_mode, .. } =
+                    change.event
+                {
+                    if !entry_mode.is_tree() {
+                        changed.insert(change.location.to_string());
+                    }
+                }
+                Ok::<_, std::convert::Infallible>(gix::object::tree::diff::Action::Continue)
+            })
+            .expect("Failed to diff trees");
+
+        // `rev..HEAD` only covers what's already committed; fold in anything
+        // still staged-but-uncommitted so "--against" matches everything
+        // touched on the branch, not just what made it into a commit.
+        for entry in self.staged_entries() {
+            changed.insert(entry.path);
+        }
+
+        // And staged entries alone miss tracked files edited but never
+        // `git add`ed; fold those in too via an index-to-worktree diff, so
+        // "--against" really does cover everything touched, staged or not.
+        let status = self
+            .inner
+            .status(gix::progress::Discard)
+            .expect("Failed to start status")
+            .untracked_files(gix::status::UntrackedFiles::None)
+            .into_index_worktree_iter(Vec::new())
+            .expect("Failed to compute index-worktree status");
+        for item in status {
+            use gix::status::index_worktree::iter::Item;
+            if let Item::Modification { rela_path, .. } = item.expect("Failed to read status entry") {
+                changed.insert(rela_path.to_string());
+            }
+        }
+
+        changed.into_iter().collect()
+    }
+
+    /// Read the content of a blob by its object id.
+    pub fn blob_content(&self, id: gix::ObjectId) -> Vec<u8> {
+        #[cfg(not(feature = "subprocess"))]
+        {
+            self.inner
+                .find_object(id)
+                .expect("Failed to read blob from object database")
+                .data
+                .clone()
+        }
+        #[cfg(feature = "subprocess")]
+        {
+            blob_content_subprocess(&self.root, id)
+        }
+    }
+
+    /// Write `content` as a new blob and return its object id.
+    pub fn write_blob(&self, content: &[u8]) -> gix::ObjectId {
+        #[cfg(not(feature = "subprocess"))]
+        {
+            self.inner
+                .write_blob(content)
+                .expect("Failed to write blob")
+                .into()
+        }
+        #[cfg(feature = "subprocess")]
+        {
+            write_blob_subprocess(&self.root, content)
+        }
+    }
+
+    /// Apply a batch of index entry updates and persist the index once.
+    ///
+    /// Formatting runs one file per worker thread, but the index itself
+    /// isn't safe for concurrent read-modify-write: loading, mutating one
+    /// entry, and writing back per file races every other in-flight write
+    /// against the same `.git/index`, and the loser's change is silently
+    /// dropped. Collecting every update and applying them in one
+    /// load-mutate*N-write sequence after the parallel stage joins avoids that.
+    pub fn update_index_entries(&self, updates: &[IndexUpdate]) {
+        if updates.is_empty() {
+            return;
+        }
+        #[cfg(not(feature = "subprocess"))]
+        {
+            let mut index = self.load_index();
+            for update in updates {
+                let Some(entry) = index.entry_mut_by_path_and_stage(
+                    update.path.as_str().into(),
+                    gix::index::entry::Stage::Unconflicted,
+                ) else {
+                    warn!("No index entry found for {}, skipping update", update.path);
+                    continue;
+                };
+                entry.id = update.id;
+                if let Ok(bits) = u32::from_str_radix(&update.mode, 8) {
+                    entry.mode = gix::index::entry::Mode::from_bits(bits).unwrap_or(entry.mode);
+                }
+            }
+            index
+                .write(gix::index::write::Options::default())
+                .expect("Failed to persist index");
+        }
+        #[cfg(feature = "subprocess")]
+        {
+            for update in updates {
+                update_index_entry_subprocess(&self.root, &update.path, update.id, &update.mode);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "subprocess")]
+fn get_git_root_subprocess() -> PathBuf {
+    let output = process::Command::new("git")
+        .arg("rev-parse")
+        .arg("--show-toplevel")
+        .output()
+        .expect("Failed to run git rev-parse --show-toplevel");
+    let git_root = std::str::from_utf8(&output.stdout)
+        .expect("Failed to parse git rev-parse --show-toplevel output")
+        .trim();
+    PathBuf::from(git_root)
+}
+
+#[cfg(feature = "subprocess")]
+fn staged_entries_subprocess() -> Vec<StagedEntry> {
+    let re = regex::Regex::new(
+        r"(\d{6}) (\d{6}) ([0-9a-f]{40}) ([0-9a-f]{40}) ([ACDMRTUXB])\d{0,3} (.+?)(?:\t(.+))?$",
+    )
+    .unwrap();
+    let output = process::Command::new("git")
+        .args([
+            "diff-index",
+            "--cached",
+            "--diff-filter=AM",
+            "--no-renames",
+            "HEAD",
+        ])
+        .output()
+        .expect("Failed to run git diff-index --cached --diff-filter=AM --no-renames HEAD");
+    let text = std::str::from_utf8(&output.stdout).expect(
+        "Failed to parse git diff-index --cached --diff-filter=AM --no-renames HEAD output",
+    );
+    text.lines()
+        .map(|line| {
+            let captures = re.captures(line).expect("Failed to parse diff");
+            StagedEntry {
+                dst_mode: captures.get(2).unwrap().as_str().to_string(),
+                dst_hash: captures.get(4).unwrap().as_str().parse().unwrap(),
+                path: captures.get(6).unwrap().as_str().to_string(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(feature = "subprocess")]
+fn changed_against_subprocess(root: &PathBuf, rev: &str) -> Vec<String> {
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["diff", "--name-only", "--diff-filter=AM", rev])
+        .output()
+        .expect("Failed to run git diff --name-only");
+    std::str::from_utf8(&output.stdout)
+        .expect("Failed to parse git diff --name-only output")
+        .lines()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+#[cfg(feature = "subprocess")]
+fn blob_content_subprocess(root: &PathBuf, id: gix::ObjectId) -> Vec<u8> {
+    process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .arg("cat-file")
+        .arg("blob")
+        .arg(id.to_string())
+        .output()
+        .expect("Failed to run git cat-file blob")
+        .stdout
+}
+
+#[cfg(feature = "subprocess")]
+fn write_blob_subprocess(root: &PathBuf, content: &[u8]) -> gix::ObjectId {
+    use std::io::Write;
+    let mut child = process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .arg("hash-object")
+        .arg("-w")
+        .arg("--stdin")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn git hash-object");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(content)
+        .expect("Failed to write to git hash-object stdin");
+    let output = child
+        .wait_with_output()
+        .expect("Failed to run git hash-object");
+    std::str::from_utf8(&output.stdout)
+        .expect("Failed to parse git hash-object output")
+        .trim()
+        .parse()
+        .expect("Failed to parse object id from git hash-object")
+}
+
+#[cfg(feature = "subprocess")]
+fn update_index_entry_subprocess(root: &PathBuf, path: &str, id: gix::ObjectId, mode: &str) {
+    process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["update-index", "--cacheinfo"])
+        .arg(format!("{},{},{}", mode, id, path))
+        .status()
+        .expect("Failed to run git update-index --cacheinfo");
+}
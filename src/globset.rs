@@ -0,0 +1,283 @@
+//! A fast pre-filter for matching many glob patterns against many paths.
+//!
+//! Running every pattern's `glob::Pattern::matches` against every candidate
+//! path is O(patterns × paths) of regex-like work. The common case, though, is dozens of patterns like `*.js` or
+//! `src/*`, which can be recognized up front and matched in O(paths) total:
+//!
+//! - patterns with no metacharacters go into an exact-match `HashSet`, hashed
+//!   with FNV (faster than SipHash for the short ASCII-ish keys paths are),
+//! - patterns of the form `*<suffix>` are merged into a single Aho-Corasick
+//!   automaton keyed on the candidate's basename,
+//! - patterns of the form `<prefix>*` are merged into a second automaton
+//!   keyed on the full path,
+//! - anything else (character classes, multiple wildcards, `**`, ...) falls
+//!   back to evaluating the full `glob::Pattern`.
+//!
+//! Aho-Corasick only narrows down *candidates*: a hit still gets its full
+//! `glob::Pattern` evaluated before being reported as a match, so behavior is
+//! identical to matching every pattern directly, just faster.
+
+use std::hash::BuildHasherDefault;
+
+use aho_corasick::AhoCorasick;
+use fnv::FnvHasher;
+use glob::{MatchOptions, Pattern};
+
+type FnvHashSet<T> = std::collections::HashSet<T, BuildHasherDefault<FnvHasher>>;
+
+struct Entry {
+    pattern: Pattern,
+    options: MatchOptions,
+}
+
+enum Classification {
+    Exact(String),
+    Suffix(String),
+    Prefix(String),
+    Complex,
+}
+
+/// Classify a glob's literal text, independent of match options. Patterns
+/// with no metacharacters are exact matches; otherwise only patterns with
+/// exactly one `*` (at the very start or the very end) qualify for the fast
+/// paths; anything with `?`, `[...]`, or more than one `*` falls back to the
+/// full glob engine.
+fn classify(text: &str) -> Classification {
+    let star_count = text.matches('*').count();
+    let has_other_meta = text.contains(['?', '[', ']']);
+
+    if star_count == 0 && !has_other_meta {
+        return Classification::Exact(text.to_string());
+    }
+    if has_other_meta || star_count != 1 {
+        return Classification::Complex;
+    }
+    if let Some(suffix) = text.strip_prefix('*') {
+        // The suffix automaton is keyed on the candidate's *basename*, which
+        // can never contain a `/`; a suffix that spans a directory boundary
+        // would never be found there, so it has to fall back to the full glob.
+        if !suffix.is_empty() && !suffix.contains('/') {
+            return Classification::Suffix(suffix.to_string());
+        }
+    }
+    if let Some(prefix) = text.strip_suffix('*') {
+        if !prefix.is_empty() {
+            return Classification::Prefix(prefix.to_string());
+        }
+    }
+    Classification::Complex
+}
+
+/// A compiled set of glob patterns, each carrying an arbitrary payload
+/// (`PathspecSet` uses this to get back to the original pattern index).
+pub struct GlobSet<T> {
+    exact: FnvHashSet<String>,
+    exact_payload: std::collections::HashMap<String, Vec<T>>,
+    suffix_ac: Option<AhoCorasick>,
+    suffix_payload: Vec<(Entry, T)>,
+    prefix_ac: Option<AhoCorasick>,
+    prefix_payload: Vec<(Entry, T)>,
+    complex: Vec<(Entry, T)>,
+}
+
+impl<T: Copy> GlobSet<T> {
+    pub fn build(patterns: Vec<(&str, MatchOptions, T)>) -> Result<Self, glob::PatternError> {
+        let mut exact = FnvHashSet::default();
+        let mut exact_payload: std::collections::HashMap<String, Vec<T>> =
+            std::collections::HashMap::new();
+        let mut suffix_patterns = Vec::new();
+        let mut suffix_payload = Vec::new();
+        let mut prefix_patterns = Vec::new();
+        let mut prefix_payload = Vec::new();
+        let mut complex = Vec::new();
+
+        for (text, options, payload) in patterns {
+            // The fast paths assume exact byte/ASCII-case comparisons, so
+            // case-insensitive patterns always fall back to the full glob.
+            let classification = if options.case_sensitive {
+                classify(text)
+            } else {
+                Classification::Complex
+            };
+            match classification {
+                Classification::Exact(key) => {
+                    exact.insert(key.clone());
+                    exact_payload.entry(key).or_default().push(payload);
+                }
+                Classification::Suffix(suffix) => {
+                    suffix_patterns.push(suffix);
+                    suffix_payload.push((
+                        Entry {
+                            pattern: Pattern::new(text)?,
+                            options,
+                        },
+                        payload,
+                    ));
+                }
+                Classification::Prefix(prefix) => {
+                    prefix_patterns.push(prefix);
+                    prefix_payload.push((
+                        Entry {
+                            pattern: Pattern::new(text)?,
+                            options,
+                        },
+                        payload,
+                    ));
+                }
+                Classification::Complex => {
+                    complex.push((
+                        Entry {
+                            pattern: Pattern::new(text)?,
+                            options,
+                        },
+                        payload,
+                    ));
+                }
+            }
+        }
+
+        let suffix_ac = (!suffix_patterns.is_empty()).then(|| {
+            AhoCorasick::new(&suffix_patterns).expect("Failed to build Aho-Corasick automaton")
+        });
+        let prefix_ac = (!prefix_patterns.is_empty()).then(|| {
+            AhoCorasick::new(&prefix_patterns).expect("Failed to build Aho-Corasick automaton")
+        });
+
+        Ok(Self {
+            exact,
+            exact_payload,
+            suffix_ac,
+            suffix_payload,
+            prefix_ac,
+            prefix_payload,
+            complex,
+        })
+    }
+
+    /// Payloads of every pattern that matches `path`, in no particular order.
+    pub fn matches(&self, path: &str) -> Vec<T> {
+        let mut out = Vec::new();
+
+        if self.exact.contains(path) {
+            out.extend(self.exact_payload[path].iter().copied());
+        }
+
+        if let Some(ac) = &self.suffix_ac {
+            let basename = path.rsplit('/').next().unwrap_or(path);
+            // Overlapping search: more than one suffix pattern (e.g. ".js" and
+            // "x.js") can legitimately match the same basename.
+            for m in ac.find_overlapping_iter(basename) {
+                // A suffix match is only real if it reaches the end of the basename.
+                if m.end() != basename.len() {
+                    continue;
+                }
+                let (entry, payload) = &self.suffix_payload[m.pattern().as_usize()];
+                if entry.pattern.matches_with(path, entry.options) {
+                    out.push(*payload);
+                }
+            }
+        }
+
+        if let Some(ac) = &self.prefix_ac {
+            for m in ac.find_overlapping_iter(path) {
+                if m.start() != 0 {
+                    continue;
+                }
+                let (entry, payload) = &self.prefix_payload[m.pattern().as_usize()];
+                if entry.pattern.matches_with(path, entry.options) {
+                    out.push(*payload);
+                }
+            }
+        }
+
+        for (entry, payload) in &self.complex {
+            if entry.pattern.matches_with(path, entry.options) {
+                out.push(*payload);
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(case_sensitive: bool) -> MatchOptions {
+        MatchOptions {
+            case_sensitive,
+            ..MatchOptions::default()
+        }
+    }
+
+    /// What a naive "evaluate every `glob::Pattern` in turn" matcher would
+    /// report, to compare the fast-pathed `GlobSet` against.
+    fn fallback_matches(patterns: &[(&str, MatchOptions, usize)], path: &str) -> Vec<usize> {
+        patterns
+            .iter()
+            .filter(|(text, options, _)| Pattern::new(text).unwrap().matches_with(path, *options))
+            .map(|(_, _, payload)| *payload)
+            .collect()
+    }
+
+    fn assert_same_matches(patterns: Vec<(&str, MatchOptions, usize)>, path: &str) {
+        let set = GlobSet::build(patterns.clone()).expect("valid patterns");
+        let mut fast: Vec<usize> = set.matches(path);
+        let mut slow = fallback_matches(&patterns, path);
+        fast.sort_unstable();
+        slow.sort_unstable();
+        assert_eq!(fast, slow, "path = {:?}, patterns = {:?}", path, patterns);
+    }
+
+    #[test]
+    fn exact_pattern_matches_only_identical_path() {
+        assert_same_matches(vec![("src/main.rs", opts(true), 0)], "src/main.rs");
+        assert_same_matches(vec![("src/main.rs", opts(true), 0)], "src/other.rs");
+    }
+
+    #[test]
+    fn suffix_pattern_matches_by_basename() {
+        let patterns = vec![("*.js", opts(true), 0), ("*.test.js", opts(true), 1)];
+        assert_same_matches(patterns.clone(), "src/main.js");
+        assert_same_matches(patterns.clone(), "src/main.test.js");
+        assert_same_matches(patterns, "src/main.rs");
+    }
+
+    #[test]
+    fn prefix_pattern_matches_by_full_path() {
+        let patterns = vec![("src/*", opts(true), 0), ("src/lib/*", opts(true), 1)];
+        assert_same_matches(patterns.clone(), "src/main.rs");
+        assert_same_matches(patterns.clone(), "src/lib/util.rs");
+        assert_same_matches(patterns, "test/main.rs");
+    }
+
+    #[test]
+    fn complex_pattern_falls_back_to_full_glob() {
+        let patterns = vec![("src/**/*.js", opts(true), 0), ("src/[ab]*.rs", opts(true), 1)];
+        assert_same_matches(patterns.clone(), "src/deep/nested/main.js");
+        assert_same_matches(patterns.clone(), "src/a.rs");
+        assert_same_matches(patterns, "src/c.rs");
+    }
+
+    #[test]
+    fn case_insensitive_pattern_falls_back_to_full_glob() {
+        let patterns = vec![("*.JS", opts(false), 0)];
+        assert_same_matches(patterns, "main.js");
+    }
+
+    #[test]
+    fn overlapping_suffix_patterns_both_match() {
+        let patterns = vec![("*.js", opts(true), 0), ("*x.js", opts(true), 1)];
+        assert_same_matches(patterns, "x.js");
+    }
+
+    #[test]
+    fn suffix_pattern_spanning_a_directory_falls_back_to_full_glob() {
+        // `*/foo.js`'s "suffix" contains a `/`, so the basename-only fast
+        // path could never find it; this must go through `Classification::Complex`.
+        let patterns = vec![("*/foo.js", opts(true), 0)];
+        assert_same_matches(patterns.clone(), "sub/foo.js");
+        assert_same_matches(patterns, "foo.js");
+    }
+}
@@ -1,9 +1,24 @@
 use clap::Parser;
-use glob::PatternError;
-use std::path::{self, PathBuf};
+use rayon::prelude::*;
+use std::path::PathBuf;
 use std::process;
 
-use log::{error, info, trace, warn};
+use log::{error, info, warn};
+
+mod git;
+mod globset;
+mod patch;
+mod pathspec;
+
+use git::{IndexUpdate, Repo, StagedEntry};
+use patch::PatchOutcome;
+use pathspec::PathspecSet;
+
+fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
 
 #[derive(Debug, Parser)]
 /// Transform staged files using a formatting command that accepts content via stdin and produces a result via stdout.
@@ -25,61 +40,17 @@ struct Cli {
     #[arg(long, action = clap::ArgAction::SetTrue)]
     verbose: bool,
 
-    /// Patterns that specify files to format. The formatter will only transform staged files that are given here. Patterns may be literal file paths, or globs which will be tested against staged file paths using Python"s fnmatch function. For example "src/*.js" will match all files with a .js extension in src/ and its subdirectories. Patterns may be negated to exclude files using a "!" character. Patterns are evaluated left-to-right. (Example: "main.js" "src/*.js" "test/*.js" "!test/todo/*")
-    #[arg(action = clap::ArgAction::Append)]
-    files: Vec<String>,
-}
-
-fn get_git_root() -> PathBuf {
-    let output = process::Command::new("git")
-        .arg("rev-parse")
-        .arg("--show-toplevel")
-        .output()
-        .expect("Failed to run git rev-parse --show-toplevel");
-    let git_root = std::str::from_utf8(&output.stdout)
-        .expect("Failed to parse git rev-parse --show-toplevel output")
-        .trim();
-    PathBuf::from(git_root)
-}
+    /// Number of files to format concurrently. Each file's formatter is an external process, so throughput is dominated by process startup latency rather than CPU; defaults to the number of available CPUs.
+    #[arg(short = 'j', long, default_value_t = default_jobs())]
+    jobs: usize,
 
-/*
-'src_mode': unless_zeroed(m.group(1)),
-'dst_mode': unless_zeroed(m.group(2)),
-'src_hash': unless_zeroed(m.group(3)),
-'dst_hash': unless_zeroed(m.group(4)),
-'status': m.group(5),
-'score': int(m.group(6)) if m.group(6) else None,
-'src_path': m.group(7),
-'dst_path': m.group(8)
-}
-*/
-struct StagedFile<'a> {
-    src_mode: &'a str,
-    dst_mode: &'a str,
-    src_hash: &'a str,
-    dst_hash: &'a str,
-    status: &'a str,
-    score: Option<i32>,
-    src_path: &'a str,
-    dst_path: &'a str,
-}
+    /// Format files that differ from <REV> (e.g. "origin/main") instead of the staged set. Matched files are read and reformatted from their current on-disk content; since they may not be staged at all, this mode never touches the index, and --no-update-working-tree has no effect (there would be nothing left to format).
+    #[arg(long, value_name = "REV")]
+    against: Option<String>,
 
-fn parse_diff<'a>(diff: &'a str) -> StagedFile<'a> {
-    let re = regex::Regex::new(
-        r"(\d{6}) (\d{6}) ([0-9a-f]{40}) ([0-9a-f]{40}) ([ACDMRTUXB])\d{0,3} (.+?)(?:\t(.+))?$",
-    )
-    .unwrap();
-    let captures = re.captures(diff).expect("Failed to parse diff");
-    StagedFile {
-        src_mode: captures.get(1).unwrap().as_str(),
-        dst_mode: captures.get(2).unwrap().as_str(),
-        src_hash: captures.get(3).unwrap().as_str(),
-        dst_hash: captures.get(4).unwrap().as_str(),
-        status: captures.get(5).unwrap().as_str(),
-        score: captures.get(6).map(|m| m.as_str().parse().unwrap()),
-        src_path: captures.get(7).unwrap().as_str(),
-        dst_path: captures.get(8).map(|m| m.as_str()).unwrap_or(""),
-    }
+    /// Patterns that specify files to format. The formatter will only transform staged files (or, with --against, changed files) that are given here. Patterns follow git's pathspec syntax: plain entries are fnmatch-style globs matched against the path relative to the repository root (e.g. "src/*.js"), and a leading magic signature changes how a pattern is interpreted, either spelled out as ":(sig1,sig2)pattern" or via the shorthands ":!pattern" (exclude) and ":/pattern" (top). Recognized signatures are "top", "literal", "glob" ("**"-aware, "*" does not cross "/"), "icase", and "exclude"/"!". A path is selected if it matches at least one non-exclude pattern and no exclude pattern. (Example: "main.js" "src/*.js" "test/*.js" ":!test/todo/*")
+    #[arg(action = clap::ArgAction::Append)]
+    files: Vec<String>,
 }
 
 fn normalize_path(relative_path: &str, git_root: Option<&PathBuf>) -> PathBuf {
@@ -89,109 +60,291 @@ fn normalize_path(relative_path: &str, git_root: Option<&PathBuf>) -> PathBuf {
     }
 }
 
-fn matches_some_path(signed_patterns: &Vec<SignedPattern>, path: &PathBuf) -> bool {
-    let path_str = match path.to_str() {
-        Some(p) => p,
-        None => {
-            warn!("Failed to convert path to string: {:?}, skip.", path);
-            return false;
-        }
-    };
-    let mut is_match = false;
-
-    for signed_pattern in signed_patterns {
-        let SignedPattern(is_pattern_positive, pattern) = signed_pattern;
-        if pattern.matches(path_str) {
-            is_match = *is_pattern_positive;
-        }
-    }
-    is_match
+/// A log line deferred until results can be flushed in input order.
+enum LogMsg {
+    Info(String),
+    Warn(String),
 }
 
 fn format_staged_files(
-    signed_patterns: &Vec<SignedPattern>,
+    pathspecs: &PathspecSet,
     formatter: &str,
-    git_root: PathBuf,
+    repo: &Repo,
     update_working_tree: bool,
     write: bool,
     verbose: bool,
+    jobs: usize,
 ) {
-    let command_get_staged = process::Command::new("git")
-        .args([
-            "diff-index",
-            "--cached",
-            "--diff-filter=AM",
-            "--no-renames",
-            "HEAD",
-        ])
-        .output()
-        .expect("Failed to run git diff-index --cached --diff-filter=AM --no-renames HEAD");
-    let staged_files = std::str::from_utf8(&command_get_staged.stdout).expect(
-        "Failed to parse git diff-index --cached --diff-filter=AM --no-renames HEAD output",
-    );
-    for line in staged_files.lines() {
-        let entry = parse_diff(line);
-        let entry_path = normalize_path(entry.src_path, Some(&git_root));
+    let mut to_format = Vec::new();
+    for entry in repo.staged_entries() {
         if entry.dst_mode == "120000" {
             // Do not process symlinks
             if verbose {
-                warn!("Skipping symlink: {}", entry_path.display());
+                warn!("Skipping symlink: {}", entry.path);
             }
             continue;
         }
-        if !matches_some_path(signed_patterns, &entry_path) {
+        if !pathspecs.is_match(&entry.path) {
             continue;
         }
-        if format_file_in_index(formatter, entry, update_working_tree, write=write, verbose=verbose)  {
-            info!("Reformatted {} with {}", entry.src_path, formatter)
+        to_format.push(entry);
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .expect("Failed to build thread pool");
+    // `Repo` wraps a `gix::Repository`, which is `Send` but not `Sync` (its
+    // object-cache fields are `RefCell`s), so worker threads can't share a
+    // `&Repo`. Each worker gets its own cloned handle instead, via a `Mutex`
+    // that is locked only when `map_init` creates state for a new rayon job
+    // split, not once per file.
+    let repo_source = std::sync::Mutex::new(repo.clone());
+    // Each file's formatter is its own subprocess, so farming them out across
+    // a pool hides process-startup latency instead of paying it serially.
+    // `collect` on a `par_iter` preserves input order regardless of which
+    // worker finished first, so the deferred logs below stay deterministic.
+    let results: Vec<(bool, Vec<LogMsg>, Option<IndexUpdate>)> = pool.install(|| {
+        to_format
+            .par_iter()
+            .map_init(
+                || repo_source.lock().expect("repo mutex poisoned").clone(),
+                |repo, entry| {
+                    format_file_in_index(repo, formatter, entry, update_working_tree, write, verbose)
+                },
+            )
+            .collect()
+    });
+
+    // The per-file stage above only decides what *would* change the index;
+    // actually applying those changes is serialized here; `.git/index` isn't
+    // safe for the concurrent read-modify-write a per-file update would do.
+    let mut updates = Vec::new();
+    for (entry, (changed, logs, update)) in to_format.iter().zip(results) {
+        for msg in logs {
+            match msg {
+                LogMsg::Info(m) => info!("{}", m),
+                LogMsg::Warn(m) => warn!("{}", m),
+            }
         }
+        if changed {
+            info!("Reformatted {} with {}", entry.path, formatter);
+        }
+        updates.extend(update);
+    }
+    repo.update_index_entries(&updates);
+}
+
+/// Run `formatter` with `input` piped to its stdin, returning its stdout, or `None`
+/// (with a warning queued onto `logs`) if it exited non-zero.
+fn run_formatter(
+    formatter: &str,
+    path: &str,
+    input: &[u8],
+    verbose: bool,
+    logs: &mut Vec<LogMsg>,
+) -> Option<Vec<u8>> {
+    let command = formatter.replace("{}", path);
+    if verbose {
+        logs.push(LogMsg::Info(format!("Running: {}", command)));
+    }
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn formatter");
+    {
+        use std::io::Write;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input)
+            .expect("Failed to write to formatter stdin");
     }
+    let output = child.wait_with_output().expect("Failed to run formatter");
+    if !output.status.success() {
+        logs.push(LogMsg::Warn(format!(
+            "Formatter exited with {} for {}, leaving it unchanged",
+            output.status, path
+        )));
+        return None;
+    }
+    Some(output.stdout)
 }
 
+/// Run `formatter` over the staged content of `entry`, writing the result back as a new blob.
+/// Returns whether the staged content was rewritten (never with `write` off, where the formatter's
+/// stdout is ignored), any log lines the caller should emit once this file's
+/// place in the original ordering comes up, and (if the index needs to be updated) the entry
+/// to apply. Writing blobs is safe to do concurrently (they're content-addressed, so two
+/// threads writing the same object just redo the same write), but the caller is responsible
+/// for applying the returned `IndexUpdate`s to `.git/index` sequentially.
 fn format_file_in_index(
+    repo: &Repo,
     formatter: &str,
-    diff_entry: StagedFile,
+    entry: &StagedEntry,
     update_working_tree: bool,
     write: bool,
     verbose: bool,
-) -> bool {
-    let orig_hash = diff_entry.dst_hash;
+) -> (bool, Vec<LogMsg>, Option<IndexUpdate>) {
+    let mut logs = Vec::new();
+    let orig = repo.blob_content(entry.dst_hash);
 
-    todo!("finish this function")
+    let Some(formatted) = run_formatter(formatter, &entry.path, &orig, verbose, &mut logs) else {
+        return (false, logs, None);
+    };
+    if !write || formatted == orig {
+        return (false, logs, None);
+    }
+
+    let new_hash = repo.write_blob(&formatted);
+
+    if update_working_tree {
+        let path = normalize_path(&entry.path, Some(repo.root()));
+        if let PatchOutcome::Skipped(reason) =
+            patch::apply_formatting_patch(&path, &orig, &formatted)
+        {
+            logs.push(LogMsg::Warn(reason));
+        }
+    }
+
+    let update = IndexUpdate {
+        path: entry.path.clone(),
+        id: new_hash,
+        mode: entry.dst_mode.clone(),
+    };
+    (true, logs, Some(update))
 }
 
-struct SignedPattern(bool, glob::Pattern);
+/// Format files that changed relative to `rev` rather than the staged set. These files may not
+/// be staged at all, so the index is never touched; the formatter runs over each file's current
+/// on-disk content and, with `write`, the result is written straight back to the working tree.
+fn format_changed_against(
+    pathspecs: &PathspecSet,
+    formatter: &str,
+    repo: &Repo,
+    rev: &str,
+    write: bool,
+    verbose: bool,
+    jobs: usize,
+) {
+    let to_format: Vec<String> = repo
+        .changed_against(rev)
+        .into_iter()
+        .filter(|path| pathspecs.is_match(path))
+        .collect();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .expect("Failed to build thread pool");
+    // See the comment in `format_staged_files`: each worker thread needs its
+    // own cloned `Repo` since `gix::Repository` is `Send` but not `Sync`.
+    let repo_source = std::sync::Mutex::new(repo.clone());
+    let results: Vec<(bool, Vec<LogMsg>)> = pool.install(|| {
+        to_format
+            .par_iter()
+            .map_init(
+                || repo_source.lock().expect("repo mutex poisoned").clone(),
+                |repo, path| format_path_on_disk(repo, formatter, path, write, verbose),
+            )
+            .collect()
+    });
 
-impl SignedPattern {
-    fn from_str(pattern: &str) -> Result<Self, PatternError> {
-        if pattern.starts_with("!") {
-            Ok(Self(false, glob::Pattern::new(&pattern[1..])?))
-        } else {
-            Ok(Self(true, glob::Pattern::new(pattern)?))
+    for (path, (changed, logs)) in to_format.iter().zip(results) {
+        for msg in logs {
+            match msg {
+                LogMsg::Info(m) => info!("{}", m),
+                LogMsg::Warn(m) => warn!("{}", m),
+            }
+        }
+        if changed {
+            info!("Reformatted {} with {}", path, formatter);
         }
     }
 }
 
+fn format_path_on_disk(
+    repo: &Repo,
+    formatter: &str,
+    path: &str,
+    write: bool,
+    verbose: bool,
+) -> (bool, Vec<LogMsg>) {
+    let mut logs = Vec::new();
+    let full_path = normalize_path(path, Some(repo.root()));
+
+    // Unlike `format_staged_files`, there's no index entry here to read a
+    // mode off of, so the symlink check has to go straight to the
+    // filesystem. `fs::write` follows symlinks, so without this a symlinked
+    // path showing up in the diff would get its *target* clobbered.
+    match std::fs::symlink_metadata(&full_path) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            if verbose {
+                logs.push(LogMsg::Warn(format!("Skipping symlink: {}", path)));
+            }
+            return (false, logs);
+        }
+        _ => {}
+    }
+
+    let orig = match std::fs::read(&full_path) {
+        Ok(content) => content,
+        Err(e) => {
+            logs.push(LogMsg::Warn(format!(
+                "Failed to read {}: {}, skipping",
+                path, e
+            )));
+            return (false, logs);
+        }
+    };
+
+    let Some(formatted) = run_formatter(formatter, path, &orig, verbose, &mut logs) else {
+        return (false, logs);
+    };
+    if !write || formatted == orig {
+        return (false, logs);
+    }
+
+    if let Err(e) = std::fs::write(&full_path, &formatted) {
+        logs.push(LogMsg::Warn(format!("Failed to write {}: {}", path, e)));
+        return (false, logs);
+    }
+
+    (true, logs)
+}
+
 fn main() {
     let cli = Cli::parse();
-    let git_root = get_git_root();
-    let signed_patterns = cli
-        .files
-        .iter()
-        .map(|pattern| match SignedPattern::from_str(pattern) {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Invalid file pattern: {}", e);
-                process::exit(1);
-            }
-        })
-        .collect();
-    format_staged_files(
-        &signed_patterns,
-        &cli.formatter,
-        git_root,
-        !cli.no_update_working_tree,
-        !cli.no_write,
-        cli.verbose,
-    );
+    let repo = Repo::discover();
+    let pathspecs = match PathspecSet::parse(&cli.files) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Invalid file pattern: {}", e);
+            process::exit(1);
+        }
+    };
+    match &cli.against {
+        Some(rev) => format_changed_against(
+            &pathspecs,
+            &cli.formatter,
+            &repo,
+            rev,
+            !cli.no_write,
+            cli.verbose,
+            cli.jobs,
+        ),
+        None => format_staged_files(
+            &pathspecs,
+            &cli.formatter,
+            &repo,
+            !cli.no_update_working_tree,
+            !cli.no_write,
+            cli.verbose,
+            cli.jobs,
+        ),
+    }
 }
@@ -0,0 +1,125 @@
+//! Patching working-tree files to match staged formatting changes without
+//! clobbering edits made on top of the staged content.
+//!
+//! Mirrors the strategy git-format-staged uses: diff the staged (`orig`)
+//! blob against the formatter's output (`formatted`), then apply that diff
+//! to the working tree file's *current* content as a patch rather than
+//! overwriting it outright. If the working copy diverged too far for the
+//! patch to apply cleanly, the working tree is left untouched; updating the
+//! index is left to the caller, whichever way the patch goes.
+
+use std::path::Path;
+
+/// Result of trying to bring a working-tree file in line with a formatting change.
+pub enum PatchOutcome {
+    /// The file was written, either directly (no unstaged changes) or via a patch.
+    Applied,
+    /// The working tree was left untouched; the `String` explains why, for the caller to log.
+    Skipped(String),
+}
+
+/// Apply the diff between `orig` and `formatted` to the on-disk file at `path`.
+pub fn apply_formatting_patch(path: &Path, orig: &[u8], formatted: &[u8]) -> PatchOutcome {
+    let (Ok(orig_text), Ok(formatted_text)) =
+        (std::str::from_utf8(orig), std::str::from_utf8(formatted))
+    else {
+        return PatchOutcome::Skipped(format!(
+            "{}: not valid UTF-8, leaving the working tree file untouched",
+            path.display()
+        ));
+    };
+
+    let current = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            return PatchOutcome::Skipped(format!(
+                "Failed to read working tree file {}: {}",
+                path.display(),
+                e
+            ))
+        }
+    };
+
+    if current == orig_text {
+        // No unstaged changes on top of what was formatted: safe to just write the result.
+        return write(path, formatted);
+    }
+
+    let patch = diffy::create_patch(orig_text, formatted_text);
+    match diffy::apply(&current, &patch) {
+        Ok(patched) => write(path, patched.as_bytes()),
+        Err(_) => PatchOutcome::Skipped(format!(
+            "{}: working tree has diverged too far from the staged content, leaving it untouched",
+            path.display()
+        )),
+    }
+}
+
+fn write(path: &Path, content: &[u8]) -> PatchOutcome {
+    match std::fs::write(path, content) {
+        Ok(()) => PatchOutcome::Applied,
+        Err(e) => PatchOutcome::Skipped(format!("Failed to write {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_tmp(name: &str, content: &str) -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "rgfs-patch-test-{}-{}-{:?}",
+            std::process::id(),
+            name,
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, content).expect("write temp file");
+        path
+    }
+
+    #[test]
+    fn writes_directly_when_working_tree_matches_orig() {
+        let path = write_tmp("unchanged", "const x = 1\n");
+        let outcome = apply_formatting_patch(&path, b"const x = 1\n", b"const x = 1;\n");
+        assert!(matches!(outcome, PatchOutcome::Applied));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "const x = 1;\n"
+        );
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn applies_patch_on_top_of_unrelated_working_tree_edits() {
+        // A line was added below the formatted one since staging; the patch
+        // for the formatting-only change should still apply cleanly.
+        let path = write_tmp("diverged", "const x = 1\nconst y = 2\n");
+        let outcome = apply_formatting_patch(&path, b"const x = 1\n", b"const x = 1;\n");
+        assert!(matches!(outcome, PatchOutcome::Applied));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "const x = 1;\nconst y = 2\n"
+        );
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn skips_when_working_tree_diverged_too_far() {
+        // The working copy edited the very line the formatter touched, so
+        // the patch can't apply; the file is left untouched.
+        let path = write_tmp("conflict", "const x = 99\n");
+        let outcome = apply_formatting_patch(&path, b"const x = 1\n", b"const x = 1;\n");
+        assert!(matches!(outcome, PatchOutcome::Skipped(_)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "const x = 99\n");
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn skips_non_utf8_content() {
+        let path = write_tmp("binary", "placeholder");
+        let outcome = apply_formatting_patch(&path, &[0xff, 0xfe], b"formatted");
+        assert!(matches!(outcome, PatchOutcome::Skipped(_)));
+        std::fs::remove_file(&path).ok();
+    }
+}
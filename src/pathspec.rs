@@ -0,0 +1,205 @@
+//! Git-compatible pathspec matching.
+//!
+//! A pathspec may carry a leading "magic signature" the way git's does:
+//! either the long form `:(sig1,sig2,...)pattern` or one of the shorthands
+//! `:!pattern` / `:^pattern` (exclude) and `:/pattern` (top). Recognized
+//! signatures are `top`, `literal`, `glob`, `icase`, and `exclude` (which can
+//! also be spelled `!`). A bare leading `!` with no colon is accepted too,
+//! for compatibility with the plain negated globs this tool used to support.
+//!
+//! All paths handled by this module are already relative to the repository
+//! root (they come straight from the index or a tree diff, not through
+//! `normalize_path` in `main.rs`), so `top` is effectively the
+//! default; it is still parsed so that pathspecs copied from elsewhere keep
+//! working unchanged.
+
+use glob::{MatchOptions, Pattern, PatternError};
+
+use crate::globset::GlobSet;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Magic {
+    top: bool,
+    literal: bool,
+    glob: bool,
+    icase: bool,
+    exclude: bool,
+}
+
+fn parse_signatures(raw: &str) -> Magic {
+    let mut magic = Magic::default();
+    for sig in raw.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match sig {
+            "top" => magic.top = true,
+            "literal" => magic.literal = true,
+            "glob" => magic.glob = true,
+            "icase" => magic.icase = true,
+            "exclude" | "!" => magic.exclude = true,
+            other => {
+                log::warn!("Ignoring unknown pathspec magic signature: {}", other);
+            }
+        }
+    }
+    magic
+}
+
+/// Split a raw pathspec into its magic signature and the remaining literal/glob text.
+fn split_magic(raw: &str) -> (Magic, &str) {
+    if let Some(rest) = raw.strip_prefix(":(") {
+        if let Some(end) = rest.find(')') {
+            return (parse_signatures(&rest[..end]), &rest[end + 1..]);
+        }
+    }
+    if let Some(rest) = raw.strip_prefix(":!") {
+        return (
+            Magic {
+                exclude: true,
+                ..Magic::default()
+            },
+            rest,
+        );
+    }
+    if let Some(rest) = raw.strip_prefix(":^") {
+        return (
+            Magic {
+                exclude: true,
+                ..Magic::default()
+            },
+            rest,
+        );
+    }
+    if let Some(rest) = raw.strip_prefix(":/") {
+        return (
+            Magic {
+                top: true,
+                ..Magic::default()
+            },
+            rest,
+        );
+    }
+    if let Some(rest) = raw.strip_prefix('!') {
+        return (
+            Magic {
+                exclude: true,
+                ..Magic::default()
+            },
+            rest,
+        );
+    }
+    (Magic::default(), raw)
+}
+
+struct Pathspec {
+    magic: Magic,
+    text: String,
+    options: MatchOptions,
+}
+
+impl Pathspec {
+    fn parse(raw: &str) -> Result<Self, PatternError> {
+        let (magic, text) = split_magic(raw);
+        let text = if magic.literal {
+            Pattern::escape(text)
+        } else {
+            text.to_string()
+        };
+        // Validate eagerly so a bad pattern is reported at startup rather
+        // than the first time a candidate path happens to reach it.
+        Pattern::new(&text)?;
+        let options = MatchOptions {
+            // Plain (non-`glob`) pathspecs keep this tool's historical fnmatch
+            // behavior, where `*` crosses `/`. `glob` magic opts into git's
+            // "`*` stays within a path component, `**` doesn't" semantics.
+            require_literal_separator: magic.glob,
+            case_sensitive: !magic.icase,
+            require_literal_leading_dot: false,
+        };
+        Ok(Self {
+            magic,
+            text,
+            options,
+        })
+    }
+}
+
+/// A compiled set of pathspecs, matched against candidate paths the way git does:
+/// a path is selected if it matches at least one positive pathspec and no
+/// exclude pathspec. Matching is delegated to a `GlobSet` so that the common
+/// case of many extension/prefix globs doesn't cost a full glob evaluation
+/// per pattern per path.
+pub struct PathspecSet {
+    specs: Vec<Pathspec>,
+    glob_set: GlobSet<usize>,
+}
+
+impl PathspecSet {
+    pub fn parse(raw_patterns: &[String]) -> Result<Self, PatternError> {
+        let specs = raw_patterns
+            .iter()
+            .map(|p| Pathspec::parse(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        let glob_set = GlobSet::build(
+            specs
+                .iter()
+                .enumerate()
+                .map(|(i, s)| (s.text.as_str(), s.options, i))
+                .collect(),
+        )?;
+        Ok(Self { specs, glob_set })
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        // An empty pathspec set selects nothing, not everything: a caller
+        // whose pattern list resolves to zero entries (e.g. a dynamically
+        // built glob expansion) should be a safe no-op, not "format every
+        // staged file".
+        let mut matched_positive = false;
+        for index in self.glob_set.matches(path) {
+            if self.specs[index].magic.exclude {
+                return false;
+            }
+            matched_positive = true;
+        }
+        matched_positive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(patterns: &[&str]) -> PathspecSet {
+        let patterns: Vec<String> = patterns.iter().map(|s| s.to_string()).collect();
+        PathspecSet::parse(&patterns).expect("valid pathspec")
+    }
+
+    #[test]
+    fn empty_pathspec_matches_nothing() {
+        let set = spec(&[]);
+        assert!(!set.is_match("src/main.js"));
+        assert!(!set.is_match("README.md"));
+    }
+
+    #[test]
+    fn positive_pattern_matches() {
+        let set = spec(&["*.js"]);
+        assert!(set.is_match("main.js"));
+        assert!(!set.is_match("main.rs"));
+    }
+
+    #[test]
+    fn exclude_wins_over_positive_match() {
+        let set = spec(&["src/*.js", ":!src/todo.js"]);
+        assert!(set.is_match("src/main.js"));
+        assert!(!set.is_match("src/todo.js"));
+    }
+
+    #[test]
+    fn only_exclude_patterns_match_nothing() {
+        // No positive pathspec is present, so there is nothing to opt a path
+        // in; an exclude pattern can only narrow an existing positive match.
+        let set = spec(&[":!src/todo.js"]);
+        assert!(!set.is_match("src/main.js"));
+        assert!(!set.is_match("src/todo.js"));
+    }
+}